@@ -0,0 +1,400 @@
+// import shell history from an arbitrary CSV (or TSV, or any other
+// delimited format) export.
+//
+// Unlike the other importers, there's no fixed schema to target here - the
+// caller tells us which column holds what via a `field=column` mapping, e.g.
+//
+//   --map timestamp=when,command=cmd,exit=code,cwd=dir,duration=dur
+//
+// so this is the on-ramp for anything that doesn't have a dedicated
+// importer: export it to CSV, describe the columns, and go.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use eyre::{eyre, Result};
+use log::warn;
+
+use super::Importer;
+use crate::history::History;
+use crate::import::Loader;
+
+/// The fields we know how to pull out of a row. Every one of these is
+/// optional in the source file except `command` and `timestamp`; anything
+/// else defaults to
+/// the same values the other importers fall back to when they don't know
+/// better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Field {
+    Timestamp,
+    Command,
+    Exit,
+    Cwd,
+    Duration,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "timestamp" => Ok(Self::Timestamp),
+            "command" => Ok(Self::Command),
+            "exit" => Ok(Self::Exit),
+            "cwd" => Ok(Self::Cwd),
+            "duration" => Ok(Self::Duration),
+            other => Err(eyre!("unknown CSV import field '{other}'")),
+        }
+    }
+}
+
+/// `atuin import csv` flags. Every flag also falls back to the matching
+/// env var (clap's `env` attribute), so the file/map/format/delimiter can
+/// still be scripted without passing them on the command line.
+#[derive(Parser, Debug, Clone)]
+pub struct CsvArgs {
+    /// CSV/TSV file to import
+    #[arg(long, env = "ATUIN_IMPORT_CSV_FILE")]
+    file: PathBuf,
+
+    /// Column mapping, e.g. timestamp=when,command=cmd,exit=code,cwd=dir,duration=dur
+    #[arg(long, env = "ATUIN_IMPORT_CSV_MAP")]
+    map: String,
+
+    /// chrono format the timestamp column is in
+    #[arg(
+        long,
+        env = "ATUIN_IMPORT_CSV_TIMESTAMP_FORMAT",
+        default_value = "%Y-%m-%d %H:%M:%S"
+    )]
+    timestamp_format: String,
+
+    /// Field delimiter, e.g. ',' for CSV or a literal tab for TSV
+    #[arg(long, env = "ATUIN_IMPORT_CSV_DELIMITER", default_value = ",")]
+    delimiter: String,
+}
+
+/// Parsed description of how to read a CSV/TSV export: which column holds
+/// which field, what delimiter it uses, and what format its timestamps are
+/// in.
+#[derive(Debug, Clone)]
+pub struct CsvConfig {
+    pub path: PathBuf,
+    pub delimiter: u8,
+    pub timestamp_format: String,
+    /// field -> column name, as given in `--map timestamp=when,...`
+    pub column_map: HashMap<Field, String>,
+}
+
+impl CsvConfig {
+    /// Parse a `--map` value of the form `field=column,field=column,...`.
+    fn parse_map(map: &str) -> Result<HashMap<Field, String>> {
+        map.split(',')
+            .map(|pair| {
+                let (field, column) = pair
+                    .split_once('=')
+                    .ok_or_else(|| eyre!("malformed --map entry '{pair}', expected field=column"))?;
+                Ok((Field::parse(field.trim())?, column.trim().to_string()))
+            })
+            .collect()
+    }
+
+    /// Build a config from already-parsed `CsvArgs`, e.g. from the real CLI
+    /// dispatcher that matched `atuin import csv ...` off argv.
+    pub fn from_args(args: CsvArgs) -> Result<Self> {
+        let delimiter = *args.delimiter.as_bytes().first().unwrap_or(&b',');
+
+        Ok(Self {
+            path: args.file,
+            delimiter,
+            timestamp_format: args.timestamp_format,
+            column_map: Self::parse_map(&args.map)?,
+        })
+    }
+
+    /// Build a config from `ATUIN_IMPORT_CSV_FILE`/`_MAP`/
+    /// `_TIMESTAMP_FORMAT`/`_DELIMITER`.
+    ///
+    /// This can't reuse `CsvArgs::try_parse()`: by the time `Csv::new()`
+    /// runs, the real CLI has already matched `atuin import csv ...` off
+    /// argv, so a second `try_parse()` here would choke on the leftover
+    /// `import`/`csv` tokens instead of seeing a clean set of flags. Reading
+    /// the env vars directly sidesteps that, at the cost of duplicating the
+    /// env var names and defaults `CsvArgs` declares in its `#[arg]`
+    /// attributes.
+    pub fn from_env() -> Result<Self> {
+        let file = std::env::var("ATUIN_IMPORT_CSV_FILE")
+            .map_err(|_| eyre!("ATUIN_IMPORT_CSV_FILE is not set"))?;
+        let map = std::env::var("ATUIN_IMPORT_CSV_MAP")
+            .map_err(|_| eyre!("ATUIN_IMPORT_CSV_MAP is not set"))?;
+        let timestamp_format = std::env::var("ATUIN_IMPORT_CSV_TIMESTAMP_FORMAT")
+            .unwrap_or_else(|_| "%Y-%m-%d %H:%M:%S".to_string());
+        let delimiter =
+            std::env::var("ATUIN_IMPORT_CSV_DELIMITER").unwrap_or_else(|_| ",".to_string());
+        let delimiter = *delimiter.as_bytes().first().unwrap_or(&b',');
+
+        Ok(Self {
+            path: PathBuf::from(file),
+            delimiter,
+            timestamp_format,
+            column_map: Self::parse_map(&map)?,
+        })
+    }
+}
+
+/// One successfully-parsed row, ready to become a [`History`]. Anything
+/// that failed to parse is skipped upstream rather than ending up here.
+#[derive(Debug)]
+struct CsvEntry {
+    timestamp: DateTime<Utc>,
+    command: String,
+    cwd: String,
+    exit: i64,
+    duration: i64,
+}
+
+impl From<CsvEntry> for History {
+    fn from(entry: CsvEntry) -> Self {
+        History::new(
+            entry.timestamp,
+            entry.command,
+            entry.cwd,
+            entry.exit,
+            entry.duration,
+            None,
+            None,
+        )
+    }
+}
+
+impl CsvEntry {
+    /// Try to build an entry out of a single CSV record, using `headers` to
+    /// find the columns `config.column_map` points at.
+    fn from_record(
+        record: &::csv::StringRecord,
+        headers: &::csv::StringRecord,
+        config: &CsvConfig,
+    ) -> Result<Self> {
+        let field = |field: Field| -> Option<&str> {
+            let column = config.column_map.get(&field)?;
+            let index = headers.iter().position(|h| h == column)?;
+            record.get(index)
+        };
+
+        let command = field(Field::Command)
+            .ok_or_else(|| eyre!("missing command column"))?
+            .to_string();
+
+        let timestamp = field(Field::Timestamp).ok_or_else(|| eyre!("missing timestamp column"))?;
+        let timestamp =
+            chrono::NaiveDateTime::parse_from_str(timestamp, &config.timestamp_format)
+                .map_err(|e| eyre!("could not parse timestamp '{timestamp}': {e}"))?;
+
+        Ok(Self {
+            timestamp: DateTime::from_utc(timestamp, Utc),
+            command,
+            cwd: field(Field::Cwd).unwrap_or("").to_string(),
+            exit: parse_optional_i64(field(Field::Exit), "exit")?,
+            duration: parse_optional_i64(field(Field::Duration), "duration")?,
+        })
+    }
+}
+
+/// Parses a column that defaults to `0` when the row doesn't have it at
+/// all or its cell is empty, but is treated as malformed (an `Err`, so the
+/// row gets skipped and counted like any other bad row) when the cell holds
+/// a value that isn't a number.
+fn parse_optional_i64(value: Option<&str>, field_name: &str) -> Result<i64> {
+    match value {
+        None | Some("") => Ok(0),
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| eyre!("could not parse {field_name} '{value}': {e}")),
+    }
+}
+
+#[derive(Debug)]
+pub struct Csv {
+    entries: Vec<CsvEntry>,
+}
+
+impl Csv {
+    /// Build an importer from an already-built [`CsvConfig`], e.g. one made
+    /// with [`CsvConfig::from_args`].
+    pub fn from_config(config: &CsvConfig) -> Result<Self> {
+        Ok(Self {
+            entries: Self::entries_from_config(config)?,
+        })
+    }
+
+    /// Stream `config.path` through the csv reader, converting each row to
+    /// a [`CsvEntry`]; malformed rows are skipped with a warning rather
+    /// than failing the whole import.
+    fn entries_from_config(config: &CsvConfig) -> Result<Vec<CsvEntry>> {
+        let mut reader = ::csv::ReaderBuilder::new()
+            .delimiter(config.delimiter)
+            .from_path(&config.path)?;
+
+        let headers = reader.headers()?.clone();
+
+        let mut entries = Vec::new();
+        let mut skipped = 0;
+        for record in reader.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) if !e.is_io_error() => {
+                    skipped += 1;
+                    warn!("skipping malformed CSV row: {e}");
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match CsvEntry::from_record(&record, &headers, config) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    skipped += 1;
+                    warn!("skipping malformed CSV row: {e}");
+                }
+            }
+        }
+
+        if skipped > 0 {
+            warn!("skipped {skipped} malformed row(s) while importing {:?}", config.path);
+        }
+
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl Importer for Csv {
+    const NAME: &'static str = "csv";
+
+    /// Creates a new Csv importer, reading the file/column-mapping config
+    /// from `ATUIN_IMPORT_CSV_FILE`/`_MAP`/`_TIMESTAMP_FORMAT`/`_DELIMITER`
+    /// (see [`CsvConfig::from_env`]) and parsing the file. A CLI dispatcher
+    /// that already has a parsed `CsvArgs` in hand should build a
+    /// [`CsvConfig`] with [`CsvConfig::from_args`] and pass it to
+    /// [`Csv::from_config`] instead of going through this trait method.
+    async fn new() -> Result<Self> {
+        let config = CsvConfig::from_env()?;
+        Ok(Self {
+            entries: Self::entries_from_config(&config)?,
+        })
+    }
+    async fn entries(&mut self) -> Result<usize> {
+        Ok(self.entries.len())
+    }
+    async fn load(self, h: &mut impl Loader) -> Result<()> {
+        for i in self.entries {
+            h.push(i.into()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(map: &str) -> CsvConfig {
+        CsvConfig {
+            path: PathBuf::new(),
+            delimiter: b',',
+            timestamp_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            column_map: CsvConfig::parse_map(map).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let config = config("timestamp=when,command=cmd,exit=code,cwd=dir,duration=dur");
+        assert_eq!(config.column_map.get(&Field::Command).unwrap(), "cmd");
+        assert_eq!(config.column_map.get(&Field::Timestamp).unwrap(), "when");
+    }
+
+    #[test]
+    fn test_csv_args_parses_real_flags() {
+        let args = CsvArgs::try_parse_from([
+            "csv",
+            "--file",
+            "history.csv",
+            "--map",
+            "timestamp=when,command=cmd",
+            "--delimiter",
+            ";",
+        ])
+        .unwrap();
+
+        let config = CsvConfig::from_args(args).unwrap();
+        assert_eq!(config.path, PathBuf::from("history.csv"));
+        assert_eq!(config.delimiter, b';');
+        assert_eq!(config.column_map.get(&Field::Command).unwrap(), "cmd");
+    }
+
+    // `from_env` reads `std::env::var` directly rather than re-parsing argv
+    // (see the doc comment on `CsvConfig::from_env`), so unlike
+    // `test_csv_args_parses_real_flags` this exercises the path `Csv::new()`
+    // actually takes - reached well after the real CLI has already consumed
+    // `atuin import csv ...` off argv.
+    #[test]
+    fn test_csv_config_from_env_reads_env_vars() {
+        std::env::set_var("ATUIN_IMPORT_CSV_FILE", "history.csv");
+        std::env::set_var("ATUIN_IMPORT_CSV_MAP", "timestamp=when,command=cmd");
+        std::env::set_var("ATUIN_IMPORT_CSV_DELIMITER", ";");
+
+        let config = CsvConfig::from_env().unwrap();
+
+        assert_eq!(config.path, PathBuf::from("history.csv"));
+        assert_eq!(config.delimiter, b';');
+        assert_eq!(config.column_map.get(&Field::Command).unwrap(), "cmd");
+
+        std::env::remove_var("ATUIN_IMPORT_CSV_FILE");
+        std::env::remove_var("ATUIN_IMPORT_CSV_MAP");
+        std::env::remove_var("ATUIN_IMPORT_CSV_DELIMITER");
+    }
+
+    #[test]
+    fn test_from_record() {
+        let config = config("timestamp=when,command=cmd,cwd=dir");
+        let headers = ::csv::StringRecord::from(vec!["when", "cmd", "dir"]);
+        let record =
+            ::csv::StringRecord::from(vec!["2022-05-02 12:25:18", "pwd", "/home/noyez"]);
+
+        let entry = CsvEntry::from_record(&record, &headers, &config).unwrap();
+        assert_eq!(entry.command, "pwd");
+        assert_eq!(entry.cwd, "/home/noyez");
+        assert_eq!(entry.exit, 0);
+        assert_eq!(entry.duration, 0);
+    }
+
+    #[test]
+    fn test_from_record_missing_command_is_malformed() {
+        let config = config("timestamp=when,command=cmd");
+        let headers = ::csv::StringRecord::from(vec!["when"]);
+        let record = ::csv::StringRecord::from(vec!["2022-05-02 12:25:18"]);
+
+        assert!(CsvEntry::from_record(&record, &headers, &config).is_err());
+    }
+
+    #[test]
+    fn test_from_record_missing_exit_defaults_to_zero() {
+        let config = config("timestamp=when,command=cmd");
+        let headers = ::csv::StringRecord::from(vec!["when", "cmd"]);
+        let record = ::csv::StringRecord::from(vec!["2022-05-02 12:25:18", "pwd"]);
+
+        let entry = CsvEntry::from_record(&record, &headers, &config).unwrap();
+        assert_eq!(entry.exit, 0);
+    }
+
+    #[test]
+    fn test_from_record_unparseable_exit_is_malformed() {
+        let config = config("timestamp=when,command=cmd,exit=code");
+        let headers = ::csv::StringRecord::from(vec!["when", "cmd", "code"]);
+        let record = ::csv::StringRecord::from(vec!["2022-05-02 12:25:18", "pwd", "N/A"]);
+
+        assert!(CsvEntry::from_record(&record, &headers, &config).is_err());
+    }
+}
@@ -25,7 +25,7 @@
 //                       old_dir TEXT);
 //
 
-use std::convert::TryInto;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
@@ -50,42 +50,301 @@ pub struct McFlyEntry {
     pub exit_code: i64,
     pub cmd: String,
     pub dir: String,
+    pub session_id: String,
 }
 
-impl From<McFlyEntry> for History {
-    fn from(mcfly_item: McFlyEntry) -> Self {
-        let dt = NaiveDateTime::from_timestamp(mcfly_item.when_run.timestamp(), mcfly_item.id.try_into().unwrap()); // try to use id as nanosecs of timestamp
-        History::new(
-            DateTime::from_utc(dt, Utc), // must assume UTC?
-            mcfly_item.cmd,
-            mcfly_item.dir,
-            mcfly_item.exit_code,
-            0, // assume 0, we have no way of knowing :(
-            None,
-            None,
-        )
+/// How many ticks we reserve per second for the within-second ordering
+/// counter below - plenty of headroom for even a very fast shell session,
+/// while staying well under a nanosecond's `u32::MAX`.
+const TICKS_PER_SECOND: u32 = 1_000_000;
+
+/// FNV-1a, 64-bit variant. `std`'s `DefaultHasher` explicitly disclaims any
+/// stability across Rust/std versions, which `atuin_session_id` below can't
+/// afford - we need the same mcfly session id to always hash to the same
+/// atuin one, even after an atuin rebuild against a different std.
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes
+        .iter()
+        .fold(OFFSET_BASIS, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME))
+}
+
+/// mcfly's `session_id` is an opaque per-invocation string with no
+/// relationship to atuin's own session ids. Hash it into a stable atuin
+/// session id so the same mcfly session always maps to the same atuin one,
+/// without having to invent a shared id format between the two tools.
+fn atuin_session_id(mcfly_session_id: &str) -> String {
+    format!("{:016x}", fnv1a64(mcfly_session_id.as_bytes()))
+}
+
+/// Estimates each entry's duration as the gap to the next entry recorded in
+/// the same `session_id`, in nanoseconds - mcfly only records one timestamp
+/// per row, so that gap is the only proxy we have for "how long did this
+/// command run". The last entry in a session (or a gap that comes out
+/// negative or zero, e.g. clock skew between rows) falls back to `0`, same
+/// as when we didn't have an estimate at all.
+fn estimate_durations_ns(entries: &[McFlyEntry]) -> Vec<i64> {
+    let mut durations = vec![0i64; entries.len()];
+    let mut last_in_session: HashMap<&str, usize> = HashMap::new();
+
+    for (i, entry) in entries.iter().enumerate() {
+        if let Some(&prev) = last_in_session.get(entry.session_id.as_str()) {
+            let gap_secs = entry.when_run.timestamp() - entries[prev].when_run.timestamp();
+            if gap_secs > 0 {
+                durations[prev] = gap_secs.saturating_mul(1_000_000_000);
+            }
+        }
+        last_in_session.insert(entry.session_id.as_str(), i);
     }
+
+    durations
+}
+
+/// Turns mcfly entries (ordered by `id`, i.e. the order mcfly recorded
+/// them in) into [`History`], preserving their original session grouping
+/// and keeping entries that share a `when_run` second in their original
+/// relative order.
+///
+/// We used to stuff `id` into the timestamp's nanosecond field to fake
+/// uniqueness; that destroyed real chronology whenever `id` didn't line up
+/// with `when_run`. Instead we keep a monotonic counter that only resets
+/// when `when_run` actually changes, so ties are broken by import order
+/// rather than by an unrelated number.
+fn to_history(entries: Vec<McFlyEntry>) -> Vec<History> {
+    let durations = estimate_durations_ns(&entries);
+    let mut session_ids: HashMap<String, String> = HashMap::new();
+    let mut last_second: Option<i64> = None;
+    let mut tick: u32 = 0;
+
+    entries
+        .into_iter()
+        .zip(durations)
+        .map(|(entry, duration)| {
+            let second = entry.when_run.timestamp();
+            tick = match last_second {
+                Some(s) if s == second => tick + 1,
+                _ => 0,
+            };
+            last_second = Some(second);
+
+            let session = session_ids
+                .entry(entry.session_id.clone())
+                .or_insert_with(|| atuin_session_id(&entry.session_id))
+                .clone();
+
+            let dt = NaiveDateTime::from_timestamp(second, tick.min(TICKS_PER_SECOND - 1));
+
+            History::new(
+                DateTime::from_utc(dt, Utc), // must assume UTC?
+                entry.cmd,
+                entry.dir,
+                entry.exit_code,
+                duration,
+                Some(session),
+                None,
+            )
+        })
+        .collect()
 }
 
+/// Env var checked for a SQLCipher passphrase, in addition to the shared
+/// `ATUIN_IMPORT_KEY`.
+const MCFLY_HISTORY_KEY_ENV: &str = "MCFLY_HISTORY_KEY";
+
 #[derive(Debug)]
 pub struct McFly {
     entries: Vec<McFlyEntry>,
 }
 
 /// Read db at given file, return vector of entries.
+///
+/// If `MCFLY_HISTORY_KEY`/`ATUIN_IMPORT_KEY` is set, the database is
+/// treated as SQLCipher-encrypted and opened accordingly (see
+/// `hist_from_encrypted_db`). Otherwise we open it read-only so we don't
+/// fight mcfly (or anything else) for a write lock; if the file is too
+/// busy even for that, we read from a temporary snapshot instead and
+/// clean it up once we're done.
 async fn hist_from_db(dbpath: PathBuf) -> Result<Vec<McFlyEntry>> {
-    let pool = SqlitePool::connect(dbpath.to_str().unwrap()).await?;
-    hist_from_db_conn(pool).await
+    if let Some(key) = super::CipherKey::from_env(MCFLY_HISTORY_KEY_ENV) {
+        return hist_from_encrypted_db(&dbpath, &key);
+    }
+
+    let (pool, snapshot) = super::open_readonly(&dbpath).await?;
+    let entries = hist_from_db_conn(pool).await;
+
+    if let Some(snapshot) = snapshot {
+        super::remove_snapshot(&snapshot);
+    }
+
+    entries
+}
+
+/// The optional `commands` columns whose presence varies between mcfly
+/// schema versions. Columns not in this set (`id`, `when_run`, `cmd`) have
+/// been there since the very first schema and are always selected as-is.
+#[derive(Debug, Clone, Copy, Default)]
+struct ColumnSet {
+    dir: bool,
+    exit_code: bool,
+    old_dir: bool,
+    selected: bool,
+    session_id: bool,
+}
+
+impl ColumnSet {
+    /// Probe `commands` for the columns this schema actually has, so a
+    /// missing one can be defaulted instead of failing the query outright.
+    async fn detect(pool: &Pool<sqlx::Sqlite>) -> Result<Self> {
+        #[derive(sqlx::FromRow)]
+        struct ColumnInfo {
+            name: String,
+        }
+
+        let columns: Vec<ColumnInfo> = sqlx::query_as("PRAGMA table_info(commands)")
+            .fetch_all(pool)
+            .await?;
+        let names: HashSet<String> = columns.into_iter().map(|c| c.name).collect();
+
+        Ok(Self {
+            dir: names.contains("dir"),
+            exit_code: names.contains("exit_code"),
+            old_dir: names.contains("old_dir"),
+            selected: names.contains("selected"),
+            session_id: names.contains("session_id"),
+        })
+    }
+
+    /// Same probe as `detect`, against a `rusqlite` connection - used for
+    /// SQLCipher-encrypted databases, which `sqlx`'s plain-sqlite driver
+    /// can't open at all (see `super::open_encrypted`).
+    fn detect_sync(conn: &rusqlite::Connection) -> Result<Self> {
+        let mut stmt = conn.prepare("PRAGMA table_info(commands)")?;
+        let names: HashSet<String> = stmt
+            .query_map([], |row| row.get::<_, String>("name"))?
+            .collect::<std::result::Result<_, _>>()?;
+
+        Ok(Self {
+            dir: names.contains("dir"),
+            exit_code: names.contains("exit_code"),
+            old_dir: names.contains("old_dir"),
+            selected: names.contains("selected"),
+            session_id: names.contains("session_id"),
+        })
+    }
+}
+
+/// Builds the `commands` query, mapping any optional column this schema
+/// doesn't have (per `ColumnSet::detect`) to a sensible default rather than
+/// failing the whole import. One query works across every schema version
+/// we know about - `selected` (v1), `old_dir` (v2) and `session_id`/`dir`
+/// (v3) have all come and gone, but none of that changes how the other
+/// columns are read, so there's no per-version behaviour to dispatch on.
+///
+/// `dir`, `exit_code` and `session_id` are the only optional columns
+/// `McFlyEntry` reads today; `old_dir`/`selected` are probed for and ignored,
+/// kept around so a future entry shape can start using them without another
+/// schema probe.
+fn select_commands(cols: ColumnSet) -> String {
+    let dir = if cols.dir { "commands.dir" } else { "''" };
+    let exit_code = if cols.exit_code {
+        "commands.exit_code"
+    } else {
+        "0"
+    };
+    let session_id = if cols.session_id {
+        "commands.session_id"
+    } else {
+        "''"
+    };
+
+    format!(
+        "select commands.id, commands.when_run, {exit_code} as exit_code, commands.cmd, {dir} as dir, {session_id} as session_id from commands order by commands.id"
+    )
+}
+
+/// mcfly `schema_versions` values we know about. We don't need a different
+/// query per version (see `select_commands`), but we still want a clear
+/// error rather than silently querying a future schema we've never seen.
+const KNOWN_SCHEMA_VERSIONS: &[i64] = &[1, 2, 3];
+
+/// Read the highest version recorded in `schema_versions`. mcfly stamps
+/// every migration it has run in there, so the max is the schema currently
+/// in effect.
+async fn detect_schema_version(pool: &Pool<sqlx::Sqlite>) -> Result<i64> {
+    // `max(...)` is an aggregate, so this always returns exactly one row -
+    // `NULL` rather than no row at all when the table is empty - so the
+    // column has to be decoded as `Option<i64>`, or the empty case fails
+    // with a column-decode error instead of reaching `ok_or_else` below.
+    let (version,): (Option<i64>,) = sqlx::query_as("select max(version) from schema_versions")
+        .fetch_one(pool)
+        .await?;
+
+    version.ok_or_else(|| {
+        eyre!("could not determine the mcfly schema version (schema_versions is empty)")
+    })
+}
+
+/// Same lookup as `detect_schema_version`, against a `rusqlite` connection.
+fn detect_schema_version_sync(conn: &rusqlite::Connection) -> Result<i64> {
+    conn.query_row("select max(version) from schema_versions", [], |row| {
+        row.get::<_, Option<i64>>(0)
+    })?
+    .ok_or_else(|| eyre!("could not determine the mcfly schema version (schema_versions is empty)"))
 }
 
 async fn hist_from_db_conn(pool: Pool<sqlx::Sqlite>) -> Result<Vec<McFlyEntry>> {
-    let query = "select commands.id, commands.when_run, commands.exit_code, commands.cmd, commands.dir from commands order by commands.id";
-    let myflydb_vec: Vec<McFlyEntry> = sqlx::query_as::<_, McFlyEntry>(query)
+    let version = detect_schema_version(&pool).await?;
+    if !KNOWN_SCHEMA_VERSIONS.contains(&version) {
+        return Err(eyre!(
+            "unsupported mcfly schema version {version}; atuin knows about versions {KNOWN_SCHEMA_VERSIONS:?}"
+        ));
+    }
+
+    let columns = ColumnSet::detect(&pool).await?;
+    let query = select_commands(columns);
+
+    let myflydb_vec: Vec<McFlyEntry> = sqlx::query_as::<_, McFlyEntry>(&query)
         .fetch_all(&pool)
         .await?;
     Ok(myflydb_vec)
 }
 
+/// Read a SQLCipher-encrypted mcfly database with `key`. `rusqlite` is
+/// synchronous, so unlike `hist_from_db_conn` this doesn't run on the async
+/// runtime - the databases involved are small enough that a blocking read
+/// here isn't worth the ceremony of `spawn_blocking`.
+fn hist_from_encrypted_db(dbpath: &Path, key: &super::CipherKey) -> Result<Vec<McFlyEntry>> {
+    let conn = super::open_encrypted(dbpath, key, MCFLY_HISTORY_KEY_ENV)?;
+
+    let version = detect_schema_version_sync(&conn)?;
+    if !KNOWN_SCHEMA_VERSIONS.contains(&version) {
+        return Err(eyre!(
+            "unsupported mcfly schema version {version}; atuin knows about versions {KNOWN_SCHEMA_VERSIONS:?}"
+        ));
+    }
+
+    let columns = ColumnSet::detect_sync(&conn)?;
+    let query = select_commands(columns);
+
+    let mut stmt = conn.prepare(&query)?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok(McFlyEntry {
+                id: row.get(0)?,
+                when_run: NaiveDateTime::from_timestamp(row.get::<_, i64>(1)?, 0),
+                exit_code: row.get(2)?,
+                cmd: row.get(3)?,
+                dir: row.get(4)?,
+                session_id: row.get(5)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    Ok(entries)
+}
+
 impl McFly {
     pub fn path_candidate() -> PathBuf {
         // TODO: This needs work - mcfly has multiple default paths
@@ -125,8 +384,8 @@ impl Importer for McFly {
         Ok(self.entries.len())
     }
     async fn load(self, h: &mut impl Loader) -> Result<()> {
-        for i in self.entries {
-            h.push(i.into()).await?;
+        for i in to_history(self.entries) {
+            h.push(i).await?;
         }
         Ok(())
     }
@@ -197,6 +456,18 @@ mod test {
                 when_run INTEGER NOT NULL);
         CREATE UNIQUE INDEX IF NOT EXISTS schema_versions_index ON schema_versions (version);
         INSERT INTO schema_versions (version, when_run) VALUES (3, strftime('%s','now'));
+        CREATE TABLE commands(id INTEGER PRIMARY KEY AUTOINCREMENT,
+                              cmd TEXT NOT NULL, cmd_tpl TEXT,
+                              session_id TEXT NOT NULL,
+                              when_run INTEGER NOT NULL,
+                              exit_code INTEGER NOT NULL,
+                              selected INTEGER NOT NULL,
+                              dir TEXT,
+                              old_dir TEXT);
+        INSERT INTO commands (cmd, session_id, when_run, exit_code, selected, dir)
+            VALUES ('pwd', 'abc', 1651497918, 0, 0, '/home/noyez');
+        INSERT INTO commands (cmd, session_id, when_run, exit_code, selected, dir)
+            VALUES ('curl google.com', 'abc', 1651497923, 0, 0, '/home/noyez');
         COMMIT;
         "#;
 
@@ -204,6 +475,7 @@ mod test {
 
         // test mcfly iterator
         let hist_vec = hist_from_db_conn(pool).await.unwrap();
+        assert_eq!(hist_vec.len(), 2);
         let mcfly = McFly { entries: hist_vec };
 
         println!("h: {:#?}", mcfly.entries);
@@ -212,4 +484,191 @@ mod test {
             println!("{:?}", i);
         }
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_import_missing_optional_columns() {
+        let pool: SqlitePool = SqlitePoolOptions::new()
+            .min_connections(2)
+            .connect(":memory:")
+            .await
+            .unwrap();
+
+        // an older `commands` table with no `dir`/`exit_code` columns at
+        // all - the importer should default those rather than error out.
+        let db_sql = r#"
+        BEGIN TRANSACTION;
+        CREATE TABLE IF NOT EXISTS schema_versions(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                version INTEGER NOT NULL,
+                when_run INTEGER NOT NULL);
+        INSERT INTO schema_versions (version, when_run) VALUES (1, strftime('%s','now'));
+        CREATE TABLE commands(id INTEGER PRIMARY KEY AUTOINCREMENT,
+                              cmd TEXT NOT NULL,
+                              session_id TEXT NOT NULL,
+                              when_run INTEGER NOT NULL);
+        INSERT INTO commands (cmd, session_id, when_run) VALUES ('pwd', 'abc', 1651497918);
+        COMMIT;
+        "#;
+
+        sqlx::query(db_sql).execute(&pool).await.unwrap();
+
+        let hist_vec = hist_from_db_conn(pool).await.unwrap();
+        assert_eq!(hist_vec.len(), 1);
+        assert_eq!(hist_vec[0].dir, "");
+        assert_eq!(hist_vec[0].exit_code, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_import_unsupported_schema_version() {
+        let pool: SqlitePool = SqlitePoolOptions::new()
+            .min_connections(2)
+            .connect(":memory:")
+            .await
+            .unwrap();
+
+        let db_sql = r#"
+        BEGIN TRANSACTION;
+        CREATE TABLE IF NOT EXISTS schema_versions(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                version INTEGER NOT NULL,
+                when_run INTEGER NOT NULL);
+        INSERT INTO schema_versions (version, when_run) VALUES (99, strftime('%s','now'));
+        CREATE TABLE commands(id INTEGER PRIMARY KEY AUTOINCREMENT, cmd TEXT NOT NULL);
+        COMMIT;
+        "#;
+
+        sqlx::query(db_sql).execute(&pool).await.unwrap();
+
+        let err = hist_from_db_conn(pool).await.unwrap_err();
+        assert!(err.to_string().contains("99"));
+    }
+
+    #[test]
+    fn test_cipher_key_from_env_none_without_key() {
+        env::remove_var(MCFLY_HISTORY_KEY_ENV);
+        env::remove_var(super::super::IMPORT_KEY_ENV);
+
+        assert!(super::super::CipherKey::from_env(MCFLY_HISTORY_KEY_ENV).is_none());
+    }
+
+    /// Write a SQLCipher-encrypted mcfly database to `path` with the given
+    /// passphrase, seeded with one command - a genuinely-encrypted fixture
+    /// to prove `hist_from_encrypted_db` can read real SQLCipher output,
+    /// not just no-op past it.
+    fn write_encrypted_fixture(path: &Path, passphrase: &str) {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.pragma_update(None, "key", passphrase).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE schema_versions(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                version INTEGER NOT NULL,
+                when_run INTEGER NOT NULL);
+            INSERT INTO schema_versions (version, when_run) VALUES (3, 0);
+            CREATE TABLE commands(id INTEGER PRIMARY KEY AUTOINCREMENT,
+                                  cmd TEXT NOT NULL, cmd_tpl TEXT,
+                                  session_id TEXT NOT NULL,
+                                  when_run INTEGER NOT NULL,
+                                  exit_code INTEGER NOT NULL,
+                                  selected INTEGER NOT NULL,
+                                  dir TEXT,
+                                  old_dir TEXT);
+            INSERT INTO commands (cmd, session_id, when_run, exit_code, selected, dir)
+                VALUES ('pwd', 'abc', 1651497918, 0, 0, '/home/noyez');
+            "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_hist_from_encrypted_db_reads_a_real_sqlcipher_fixture() {
+        let dir = std::env::temp_dir().join(format!("atuin-mcfly-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let dbpath = dir.join("history.db");
+        write_encrypted_fixture(&dbpath, "correct horse battery staple");
+
+        env::set_var(MCFLY_HISTORY_KEY_ENV, "correct horse battery staple");
+        let key = super::super::CipherKey::from_env(MCFLY_HISTORY_KEY_ENV).unwrap();
+
+        let entries = hist_from_encrypted_db(&dbpath, &key).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].cmd, "pwd");
+
+        env::remove_var(MCFLY_HISTORY_KEY_ENV);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hist_from_encrypted_db_rejects_wrong_passphrase() {
+        let dir = std::env::temp_dir().join(format!("atuin-mcfly-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let dbpath = dir.join("history.db");
+        write_encrypted_fixture(&dbpath, "correct horse battery staple");
+
+        env::set_var(MCFLY_HISTORY_KEY_ENV, "wrong passphrase");
+        let key = super::super::CipherKey::from_env(MCFLY_HISTORY_KEY_ENV).unwrap();
+
+        let err = hist_from_encrypted_db(&dbpath, &key).unwrap_err();
+        assert!(err.to_string().contains("appears encrypted"));
+
+        env::remove_var(MCFLY_HISTORY_KEY_ENV);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn entry(id: i64, when_run: i64, session_id: &str) -> McFlyEntry {
+        McFlyEntry {
+            id,
+            when_run: NaiveDateTime::from_timestamp(when_run, 0),
+            exit_code: 0,
+            cmd: format!("cmd-{id}"),
+            dir: "/home/noyez".to_string(),
+            session_id: session_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_to_history_preserves_order_within_a_second() {
+        let entries = vec![
+            entry(1, 1651497918, "a"),
+            entry(2, 1651497918, "a"),
+            entry(3, 1651497919, "a"),
+        ];
+
+        let history = to_history(entries);
+
+        assert!(history[0].timestamp < history[1].timestamp);
+        assert!(history[1].timestamp < history[2].timestamp);
+    }
+
+    #[test]
+    fn test_to_history_maps_sessions_consistently() {
+        let entries = vec![entry(1, 1651497918, "a"), entry(2, 1651497919, "a")];
+
+        let history = to_history(entries);
+
+        assert_eq!(history[0].session, history[1].session);
+        assert!(!history[0].session.is_empty());
+    }
+
+    #[test]
+    fn test_to_history_estimates_duration_from_next_same_session_command() {
+        let entries = vec![
+            entry(1, 1651497918, "a"),
+            entry(2, 1651497921, "a"),
+            entry(3, 1651497930, "b"),
+        ];
+
+        let history = to_history(entries);
+
+        assert_eq!(history[0].duration, 3_000_000_000);
+        // last command in its session: no following timestamp to estimate from
+        assert_eq!(history[1].duration, 0);
+        assert_eq!(history[2].duration, 0);
+    }
+
+    #[test]
+    fn test_fnv1a64_is_deterministic() {
+        assert_eq!(fnv1a64(b"some-session"), fnv1a64(b"some-session"));
+        assert_ne!(fnv1a64(b"some-session"), fnv1a64(b"other-session"));
+    }
 }
@@ -0,0 +1,195 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use eyre::Result;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Pool,
+};
+
+pub mod csv;
+pub mod mcfly;
+
+use crate::history::History;
+
+#[async_trait]
+pub trait Importer: Sized {
+    const NAME: &'static str;
+
+    /// Creates a new Importer, parsing the history from wherever it lives on disk.
+    async fn new() -> Result<Self>;
+    async fn entries(&mut self) -> Result<usize>;
+    async fn load(self, h: &mut impl Loader) -> Result<()>;
+}
+
+#[async_trait]
+pub trait Loader: Sized + Send {
+    async fn push(&mut self, hist: History) -> Result<()>;
+}
+
+/// Open a sqlite database for reading without taking a write lock on it.
+///
+/// A lot of the tools we import from (mcfly, histdb, ...) keep the database
+/// open and writing to it while we're trying to read it, so a plain
+/// read-write connection can fail with "database is locked". We first try a
+/// real read-only, immutable connection, which sidesteps that in the common
+/// case. If the file is busy enough that even that fails, we fall back to
+/// taking a consistent snapshot of it into a temporary file with
+/// `VACUUM INTO` and read from the copy instead.
+///
+/// Returns the pool to read from, plus the path of the temporary snapshot
+/// (if one was needed) so the caller can clean it up once done.
+pub(crate) async fn open_readonly(dbpath: &Path) -> Result<(Pool<sqlx::Sqlite>, Option<PathBuf>)> {
+    let opts = SqliteConnectOptions::from_str(&format!("sqlite://{}", dbpath.display()))?
+        .read_only(true)
+        .immutable(true);
+
+    match SqlitePoolOptions::new().connect_with(opts).await {
+        Ok(pool) => Ok((pool, None)),
+        Err(err) if is_busy_or_locked(&err) => {
+            let (pool, snapshot) = snapshot_and_open(dbpath).await?;
+            Ok((pool, Some(snapshot)))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Whether `err` is sqlite telling us the file is busy/locked (codes 5 and
+/// 6), as opposed to some other failure - wrong permissions, a corrupt
+/// file, an encrypted database - that a snapshot-and-retry won't fix and
+/// would just bury under a second, more confusing error.
+fn is_busy_or_locked(err: &sqlx::Error) -> bool {
+    matches!(
+        err.as_database_error().and_then(|e| e.code()).as_deref(),
+        Some("5") | Some("6")
+    )
+}
+
+/// Copy a (possibly locked) sqlite database into a temporary file via
+/// `VACUUM INTO`, then open the copy read-write, since by this point we own
+/// the only handle to it anyway.
+///
+/// The source can contain anything the user has typed into a shell, so the
+/// copy is written into a private, mode-0700 directory rather than loose in
+/// the shared system temp dir, which is otherwise world-readable under a
+/// typical umask.
+async fn snapshot_and_open(dbpath: &Path) -> Result<(Pool<sqlx::Sqlite>, PathBuf)> {
+    let snapshot_dir = std::env::temp_dir().join(format!("atuin-import-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&snapshot_dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&snapshot_dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    let snapshot_path = snapshot_dir.join("snapshot.db");
+
+    let source = SqlitePoolOptions::new()
+        .connect(&format!("sqlite://{}", dbpath.display()))
+        .await?;
+
+    sqlx::query(&format!(
+        "VACUUM INTO '{}'",
+        snapshot_path.display()
+    ))
+    .execute(&source)
+    .await?;
+
+    source.close().await;
+
+    let pool = SqlitePoolOptions::new()
+        .connect(&format!("sqlite://{}", snapshot_path.display()))
+        .await?;
+
+    Ok((pool, snapshot_path))
+}
+
+/// Best-effort removal of a snapshot produced by [`snapshot_and_open`]:
+/// the file itself, then the private directory it lived in.
+pub(crate) fn remove_snapshot(snapshot_path: &Path) {
+    let _ = std::fs::remove_file(snapshot_path);
+    if let Some(dir) = snapshot_path.parent() {
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+/// Env var checked by every sqlite-backed importer, in addition to any
+/// importer-specific one (e.g. `MCFLY_HISTORY_KEY`).
+pub(crate) const IMPORT_KEY_ENV: &str = "ATUIN_IMPORT_KEY";
+
+/// A SQLCipher passphrase, plus the optional compatibility knobs older
+/// SQLCipher versions need to open a database written by a newer one (or
+/// vice versa).
+///
+/// `sqlx`'s sqlite driver links plain, unmodified sqlite: it doesn't know
+/// what `PRAGMA key` means and silently ignores it, so issuing that pragma
+/// over a `sqlx` connection decrypts nothing at all. Reading a SQLCipher
+/// database for real needs a codec-enabled sqlite, so callers that hold a
+/// `CipherKey` should open the database with [`open_encrypted`] (via
+/// `rusqlite`'s `bundled-sqlcipher` feature) instead of `open_readonly`.
+pub(crate) struct CipherKey {
+    passphrase: String,
+    cipher_compatibility: Option<String>,
+    kdf_iter: Option<String>,
+}
+
+impl CipherKey {
+    /// Reads a passphrase from `importer_env_key` (e.g. `MCFLY_HISTORY_KEY`)
+    /// or the shared [`IMPORT_KEY_ENV`], along with the optional
+    /// `ATUIN_IMPORT_CIPHER_COMPATIBILITY`/`ATUIN_IMPORT_KDF_ITER`
+    /// overrides. Returns `None` if no passphrase is set anywhere, so the
+    /// caller can fall back to opening the database as a plain,
+    /// unencrypted one.
+    pub(crate) fn from_env(importer_env_key: &str) -> Option<Self> {
+        let passphrase = std::env::var(importer_env_key)
+            .or_else(|_| std::env::var(IMPORT_KEY_ENV))
+            .ok()?;
+
+        Some(Self {
+            passphrase,
+            cipher_compatibility: std::env::var("ATUIN_IMPORT_CIPHER_COMPATIBILITY").ok(),
+            kdf_iter: std::env::var("ATUIN_IMPORT_KDF_ITER").ok(),
+        })
+    }
+}
+
+/// Open a SQLCipher-encrypted database read-only with `key`.
+///
+/// We sanity-check the key by running a real query against
+/// `sqlite_master` immediately after setting it: a wrong passphrase (or a
+/// file that was never encrypted to begin with) makes every following
+/// query fail the same way SQLCipher reports a bad key - sqlite's own
+/// "file is not a database" - so we catch it here and turn it into
+/// something actionable instead of letting it surface wherever the first
+/// real query happens to run.
+pub(crate) fn open_encrypted(
+    dbpath: &Path,
+    key: &CipherKey,
+    importer_env_key: &str,
+) -> Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open_with_flags(
+        dbpath,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+
+    conn.pragma_update(None, "key", &key.passphrase)?;
+    if let Some(compat) = &key.cipher_compatibility {
+        conn.pragma_update(None, "cipher_compatibility", compat)?;
+    }
+    if let Some(kdf_iter) = &key.kdf_iter {
+        conn.pragma_update(None, "kdf_iter", kdf_iter)?;
+    }
+
+    conn.query_row("select count(*) from sqlite_master", [], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map_err(|_| {
+        eyre::eyre!(
+            "database appears encrypted, or {importer_env_key}/{IMPORT_KEY_ENV} is wrong - \
+             check the passphrase"
+        )
+    })?;
+
+    Ok(conn)
+}